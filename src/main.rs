@@ -1,13 +1,30 @@
+use std::collections::HashMap;
+use std::ffi::CString;
 use std::io::Write;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{ensure, Context};
-use clap::{self, Parser};
-use ipnet::IpNet;
+use anyhow::{bail, ensure, Context};
+use chrono::Local;
+use clap::{self, Parser, ValueEnum};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use log::{debug, info, warn, LevelFilter};
+use mnl::mnl_sys::libc;
+use netlink_packet_core::{
+    NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST,
+};
+use netlink_packet_route::route::Nla as RouteNla;
+use netlink_packet_route::{RouteHeader, RouteMessage, RtnlMessage};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use nftnl::set::{Set, SetKey};
+use nftnl::{Batch, FinalizedBatch, MsgType, ProtoFamily, Table};
 use nix::sys::stat::{fchmodat, lstat, mode_t, FchmodatFlags, FileStat, Mode, SFlag};
 use nix::unistd::{chown, Gid, Uid};
 use reqwest::Url;
+use sd_notify::NotifyState;
+use serde::{Deserialize, Serialize};
 use simple_logger::SimpleLogger;
 use syslog::{BasicLogger, Facility, Formatter3164};
 use tempfile::NamedTempFile;
@@ -21,6 +38,23 @@ struct Args {
     /// Path to directory where temporary files will be created
     #[clap(short, long, value_parser, value_name = "PATH")]
     tempdir: Option<PathBuf>,
+    /// Also push the aggregated prefixes into this nftables set, given as "table/set";
+    /// destfile is still written
+    #[clap(long, value_parser = parse_nft_set, value_name = "TABLE/SET")]
+    nft_set: Option<(String, String)>,
+    /// Run continuously, refreshing every this many seconds instead of exiting after one fetch
+    #[clap(long, value_parser = parse_nonzero_interval, value_name = "SECONDS")]
+    interval: Option<u64>,
+    /// Prefixes to subtract from the aggregated set, as a URL or local file; may be repeated
+    #[clap(long, value_name = "URL-OR-FILE")]
+    exclude: Vec<String>,
+    /// Also install the aggregated prefixes as routes in this rtnetlink routing table; destfile
+    /// is still written
+    #[clap(long, value_parser, value_name = "TABLE")]
+    install_routes: Option<u32>,
+    /// Kind of route to install with --install-routes
+    #[clap(long, value_enum, default_value_t = RouteType::Blackhole)]
+    route_type: RouteType,
     /// Path to destination file
     #[clap(value_parser)]
     destfile: PathBuf,
@@ -29,6 +63,44 @@ struct Args {
     urls: Vec<Url>,
 }
 
+// The kind of null route to install with --install-routes.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RouteType {
+    Blackhole,
+    Unreachable,
+    Prohibit,
+    Throw,
+}
+
+impl RouteType {
+    // The RTN_* route kind constant rtnetlink expects in RouteHeader::kind.
+    fn rtn_kind(self) -> u8 {
+        match self {
+            RouteType::Blackhole => RTN_BLACKHOLE,
+            RouteType::Unreachable => RTN_UNREACHABLE,
+            RouteType::Prohibit => RTN_PROHIBIT,
+            RouteType::Throw => RTN_THROW,
+        }
+    }
+}
+
+// Parse a "table/set" argument into its two parts.
+fn parse_nft_set(s: &str) -> anyhow::Result<(String, String)> {
+    let (table, set) = s
+        .split_once('/')
+        .ok_or_else(|| anyhow::anyhow!("expected TABLE/SET, got {:?}", s))?;
+    ensure!(!table.is_empty() && !set.is_empty(), "table and set names must not be empty");
+    Ok((table.to_owned(), set.to_owned()))
+}
+
+// A zero interval would make run_daemon's sleep loop a no-op, spinning run_once in a tight busy
+// loop; reject it up front instead of letting that happen at runtime.
+fn parse_nonzero_interval(s: &str) -> anyhow::Result<u64> {
+    let interval: u64 = s.parse().context("not a valid number of seconds")?;
+    ensure!(interval > 0, "--interval must be greater than zero");
+    Ok(interval)
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
@@ -50,16 +122,44 @@ fn main() -> anyhow::Result<()> {
             .map(|()| log::set_max_level(LevelFilter::Info))?;
     }
 
+    match args.interval {
+        Some(interval) => run_daemon(&args, interval),
+        None => run_once(&args).map(|_| ()),
+    }
+}
+
+// Run one fetch-aggregate-publish cycle, returning the raw and aggregated prefix counts so
+// callers can report on them.
+fn run_once(args: &Args) -> anyhow::Result<(usize, usize)> {
+    // Download and aggregate the prefix lists, subtracting any --exclude sources.  URLs are
+    // fetched conditionally against a cache sidecar next to destfile, if there is one.
+    let cache_path = (args.destfile.to_str() != Some("-")).then(|| cache_sidecar_path(&args.destfile));
+    let mut cache = cache_path.as_deref().map(load_cache).unwrap_or_default();
+
+    let excludes = load_excludes(&args.exclude)?;
+    let (downloaded, nets) = download_nets(&args.urls, &excludes, &mut cache)?;
+
+    if let Some(path) = &cache_path {
+        save_cache(path, &cache)?;
+    }
+
+    if let Some((table, set)) = &args.nft_set {
+        update_nft_set(table, set, &nets)?;
+    }
+
+    if let Some(table) = args.install_routes {
+        install_routes(table, args.route_type, &nets)?;
+    }
+
     if args.destfile.to_str() == Some("-") {
         // If we're writing to stdout, we don't need a temp file.
         debug!("Writing to stdout");
-        let nets: Vec<IpNet> = download_nets(args.urls)?;
         write_nets(std::io::stdout(), &nets)?;
     } else {
         debug!("Writing to {} via temporary file", args.destfile.display());
 
         // Set up the temp file early, so we can bail before download if it fails.
-        let tmp: NamedTempFile = match args.tempdir {
+        let tmp: NamedTempFile = match args.tempdir.as_deref() {
             Some(dir) => NamedTempFile::new_in(dir),
             None => {
                 // Try to create the temp file in the same directory as destfile; if that fails,
@@ -75,7 +175,7 @@ fn main() -> anyhow::Result<()> {
         debug!("Opened temporary file {}", tmp.path().display());
 
         // Read file metadata.
-        let dest_stat: FileStat = lstat(&(args.destfile))?;
+        let dest_stat: FileStat = lstat(&args.destfile)?;
         let tmp_stat: FileStat = lstat(tmp.path())?;
 
         // Sanity checks.
@@ -100,29 +200,87 @@ fn main() -> anyhow::Result<()> {
             chown(tmp.path(), dest_uid, dest_gid)?;
         }
 
-        // Download and aggregate the prefix lists.
-        let nets: Vec<IpNet> = download_nets(args.urls)?;
         debug!("Writing network prefixes to temporary file");
         write_nets(&tmp, &nets)?;
-        // TODO: stop here if tempfile == destfile
 
-        // Make tempfile permissions the same as destfile.  Do this after writing to the tempfile
-        // in case we would make it read-only.
-        if tmp_stat.st_mode != dest_stat.st_mode {
-            let dest_mode: Mode = Mode::from_bits_truncate(dest_stat.st_mode);
-            debug!("Updating temporary file permissions to {:?}", dest_mode);
-            fchmodat(None, tmp.path(), dest_mode, FchmodatFlags::NoFollowSymlink)?
+        if files_identical(tmp.path(), &args.destfile)? {
+            debug!(
+                "{} already matches the freshly-aggregated prefixes; not replacing it",
+                args.destfile.display()
+            );
+        } else {
+            // Make tempfile permissions the same as destfile.  Do this after writing to the
+            // tempfile in case we would make it read-only.
+            if tmp_stat.st_mode != dest_stat.st_mode {
+                let dest_mode: Mode = Mode::from_bits_truncate(dest_stat.st_mode);
+                debug!("Updating temporary file permissions to {:?}", dest_mode);
+                fchmodat(None, tmp.path(), dest_mode, FchmodatFlags::NoFollowSymlink)?
+            }
+
+            // Move the tempfile over the top of the destination file.
+            tmp.as_file().sync_all()?;
+            debug!("Moving temporary file to {}", args.destfile.display());
+            let final_destfile = tmp.persist(&args.destfile)?;
+            final_destfile.sync_all()?;
+            info!("Updated {}", args.destfile.display());
+        }
+    }
+
+    Ok((downloaded, nets.len()))
+}
+
+// Loop forever, re-running `run_once` every `interval` seconds.  Notifies systemd (if run under
+// it) that we're ready after the first successful update, reports a STATUS string after every
+// cycle, and pings the watchdog once a cycle completes so a download stuck forever is noticed.
+fn run_daemon(args: &Args, interval: u64) -> anyhow::Result<()> {
+    let mut ready_sent = false;
+    let ping_period = watchdog_ping_period();
+    loop {
+        match run_once(args) {
+            Ok((downloaded, aggregated)) => {
+                let status = format!(
+                    "Downloaded {}, aggregated to {} at {}",
+                    downloaded,
+                    aggregated,
+                    Local::now().to_rfc3339()
+                );
+                let mut states = vec![NotifyState::Status(&status)];
+                if !ready_sent {
+                    states.push(NotifyState::Ready);
+                    ready_sent = true;
+                }
+                if let Err(e) = sd_notify::notify(false, &states) {
+                    debug!("sd_notify failed (probably not running under systemd): {}", e);
+                }
+            }
+            Err(e) => warn!("Refresh cycle failed, will retry next tick: {:#}", e),
         }
 
-        // Move the tempfile over the top of the destination file.
-        tmp.as_file().sync_all()?;
-        debug!("Moving temporary file to {}", args.destfile.display());
-        let final_destfile = tmp.persist(&args.destfile)?;
-        final_destfile.sync_all()?;
-        info!("Updated {}", args.destfile.display());
+        // Ping the watchdog on its own cadence while we sleep, independent of `interval`, so an
+        // operator running with `--interval` longer than the unit's WatchdogSec doesn't get
+        // restarted mid-sleep for no reason.
+        let mut remaining = Duration::from_secs(interval);
+        while remaining > Duration::ZERO {
+            let nap = remaining.min(ping_period);
+            thread::sleep(nap);
+            remaining -= nap;
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                debug!("sd_notify watchdog ping failed: {}", e);
+            }
+        }
     }
+}
 
-    Ok(())
+// How often to ping the systemd watchdog while sleeping between refreshes: half of
+// WATCHDOG_USEC, the period systemd told us about via the environment, so a single missed ping
+// never trips it. Falls back to a fixed chunk size when we're not running under the watchdog
+// (WATCHDOG_USEC unset), so `--interval` is still slept in bounded increments.
+fn watchdog_ping_period() -> Duration {
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|usec| Duration::from_micros(usec / 2))
+        .unwrap_or(Duration::from_secs(30))
 }
 
 // True iff the given FileStat is from a symbolic link.
@@ -142,28 +300,702 @@ fn write_nets(mut dest: impl Write, nets: &[IpNet]) -> std::io::Result<()> {
     dest.flush()
 }
 
-// Fetch and aggegate lists of prefixes.
-fn download_nets(urls: Vec<Url>) -> anyhow::Result<Vec<IpNet>> {
+// A URL's cached ETag/Last-Modified validators and the body they were last seen with, so the
+// next run can send a conditional request and skip re-downloading an unchanged source.
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct UrlCacheEntry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+// Fetch and aggegate lists of prefixes, subtracting `excludes`.  Each URL is fetched
+// conditionally against `cache`, reusing the cached body on a 304 response.  A URL that fails to
+// fetch is logged and skipped rather than aborting the whole run, so a daemon-mode refresh cycle
+// survives transient HTTP failures.
+fn download_nets(
+    urls: &[Url],
+    excludes: &[IpNet],
+    cache: &mut HashMap<String, UrlCacheEntry>,
+) -> anyhow::Result<(usize, Vec<IpNet>)> {
     let webclient = reqwest::blocking::Client::new();
     let bodies: Vec<String> = urls
-        .into_iter()
-        .map(|url| {
-            webclient
-                .get(url)
-                .send()
-                .and_then(|resp| resp.error_for_status())
-                .and_then(|resp| resp.text())
-        })
-        .collect::<Result<Vec<String>, reqwest::Error>>()?;
+        .iter()
+        .filter_map(|url| fetch_conditional(&webclient, url, cache))
+        .collect();
 
     let nets: Vec<IpNet> = bodies.iter().flat_map(|body| extract_nets(body)).collect();
-    let agg_nets = IpNet::aggregate(&nets);
+    let agg_nets = trie_aggregate(&nets, excludes);
     info!(
         "Downloaded {} network prefixes, aggregated to {}",
         nets.len(),
         agg_nets.len()
     );
-    Ok(agg_nets)
+    Ok((nets.len(), agg_nets))
+}
+
+// Fetch a single URL, sending If-None-Match/If-Modified-Since from `cache` when we have them.
+// A fresh response's validators and body are stored in `cache`; a 304 reuses the cached body and
+// leaves the cache entry as it was.  Any other failure is logged and yields None so the caller
+// can skip this source.
+fn fetch_conditional(
+    webclient: &reqwest::blocking::Client,
+    url: &Url,
+    cache: &mut HashMap<String, UrlCacheEntry>,
+) -> Option<String> {
+    let mut req = webclient.get(url.clone());
+    if let Some(entry) = cache.get(url.as_str()) {
+        if let Some(etag) = &entry.etag {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let resp = match req.send().and_then(|resp| resp.error_for_status()) {
+        Ok(resp) => resp,
+        Err(e) => {
+            warn!("Failed to fetch {}: {}", url, e);
+            return None;
+        }
+    };
+
+    if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+        info!("{} unchanged since last fetch", url);
+        return cache.get(url.as_str()).map(|entry| entry.body.clone());
+    }
+
+    let etag = header_str(&resp, reqwest::header::ETAG);
+    let last_modified = header_str(&resp, reqwest::header::LAST_MODIFIED);
+    let body = match resp.text() {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to read body of {}: {}", url, e);
+            return None;
+        }
+    };
+
+    info!("{} fetched fresh", url);
+    cache.insert(
+        url.to_string(),
+        UrlCacheEntry {
+            etag,
+            last_modified,
+            body: body.clone(),
+        },
+    );
+    Some(body)
+}
+
+// The value of a response header as an owned String, if present and valid UTF-8.
+fn header_str(resp: &reqwest::blocking::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+// Path of the JSON sidecar file that records each URL's cache state across runs.
+fn cache_sidecar_path(destfile: &Path) -> PathBuf {
+    let mut name = destfile.file_name().unwrap_or_default().to_os_string();
+    name.push(".cache.json");
+    destfile.with_file_name(name)
+}
+
+// Load the URL cache from its sidecar file, treating a missing or unparseable file as an empty
+// cache (e.g. on the very first run).
+fn load_cache(path: &Path) -> HashMap<String, UrlCacheEntry> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+// Persist the URL cache to its sidecar file.
+fn save_cache(path: &Path, cache: &HashMap<String, UrlCacheEntry>) -> anyhow::Result<()> {
+    let contents = serde_json::to_string(cache).context("Failed to serialise URL cache")?;
+    std::fs::write(path, contents).context("Failed to write URL cache sidecar")
+}
+
+// True iff two files' contents are byte-for-byte identical.
+fn files_identical(a: &Path, b: &Path) -> std::io::Result<bool> {
+    Ok(std::fs::read(a)? == std::fs::read(b)?)
+}
+
+// Fetch each `--exclude` source (a URL or a local file path) and parse out the prefixes it
+// contains, using the same loose line-oriented extraction as downloaded bodies.
+fn load_excludes(sources: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    let webclient = reqwest::blocking::Client::new();
+    let mut nets = Vec::new();
+    for source in sources {
+        let body = match Url::parse(source) {
+            Ok(url) => webclient
+                .get(url)
+                .send()
+                .and_then(|resp| resp.error_for_status())
+                .and_then(|resp| resp.text())
+                .with_context(|| format!("Failed to fetch exclude source {}", source))?,
+            Err(_) => std::fs::read_to_string(source)
+                .with_context(|| format!("Failed to read exclude source {}", source))?,
+        };
+        nets.extend(extract_nets(&body));
+    }
+    Ok(nets)
+}
+
+// A node in a binary (patricia) prefix trie.  `covered` marks a node whose prefix is entirely
+// included in the output; a covered node never has children, since there's nothing more specific
+// left to distinguish once the whole subtree is included.
+struct TrieNode {
+    children: [Option<Box<TrieNode>>; 2],
+    covered: bool,
+}
+
+impl TrieNode {
+    fn new() -> Self {
+        TrieNode {
+            children: [None, None],
+            covered: false,
+        }
+    }
+
+    fn leaf() -> Self {
+        TrieNode {
+            children: [None, None],
+            covered: true,
+        }
+    }
+}
+
+// The value of address bit `pos` (0 = most significant) out of `width` total bits.
+fn bit_at(bits: u128, width: u8, pos: u8) -> usize {
+    ((bits >> (width - 1 - pos)) & 1) as usize
+}
+
+// `bits` with address bit `pos` set to `val`.
+fn set_bit(bits: u128, width: u8, pos: u8, val: usize) -> u128 {
+    let mask = 1u128 << (width - 1 - pos);
+    if val == 1 {
+        bits | mask
+    } else {
+        bits & !mask
+    }
+}
+
+// Insert a covered prefix into the trie.  Order-independent: inserting a broader prefix after a
+// narrower one collapses the narrower one's now-redundant nodes, and inserting a narrower prefix
+// under an already-covered ancestor is a no-op.
+fn trie_insert(node: &mut Option<Box<TrieNode>>, bits: u128, width: u8, depth: u8, target_len: u8) {
+    let n = node.get_or_insert_with(|| Box::new(TrieNode::new()));
+    if n.covered {
+        return;
+    }
+    if depth == target_len {
+        n.covered = true;
+        n.children = [None, None];
+        return;
+    }
+    let bit = bit_at(bits, width, depth);
+    trie_insert(&mut n.children[bit], bits, width, depth + 1, target_len);
+}
+
+// Subtract an exclude prefix from the trie.  If the exclude is equal to or broader than whatever
+// is covered here, the whole subtree is dropped.  If it falls strictly inside a covered
+// ancestor, that ancestor is split: the sibling at each level down to the exclude prefix is
+// retained whole, and the branch matching the exclude prefix is excluded further.
+fn trie_exclude(node: &mut Option<Box<TrieNode>>, bits: u128, width: u8, depth: u8, target_len: u8) {
+    let Some(n) = node.as_deref_mut() else {
+        return; // nothing covered along this path: excluding it is a no-op
+    };
+    if depth == target_len {
+        *node = None;
+        return;
+    }
+    if n.covered {
+        n.covered = false;
+        let bit = bit_at(bits, width, depth);
+        n.children[1 - bit] = Some(Box::new(TrieNode::leaf()));
+        let mut matching = Some(Box::new(TrieNode::leaf()));
+        trie_exclude(&mut matching, bits, width, depth + 1, target_len);
+        n.children[bit] = matching;
+        return;
+    }
+    let bit = bit_at(bits, width, depth);
+    trie_exclude(&mut n.children[bit], bits, width, depth + 1, target_len);
+    if n.children[0].is_none() && n.children[1].is_none() {
+        *node = None;
+    }
+}
+
+// Collect the (bits, width, prefix_len) of every covered leaf in the trie.
+fn trie_collect(node: &TrieNode, bits: u128, width: u8, depth: u8, out: &mut Vec<(u128, u8, u8)>) {
+    if node.covered {
+        out.push((bits, width, depth));
+        return;
+    }
+    for (bit, child) in node.children.iter().enumerate() {
+        if let Some(child) = child {
+            trie_collect(child, set_bit(bits, width, depth, bit), width, depth + 1, out);
+        }
+    }
+}
+
+// The (address bits widened to u128, bit width, prefix length) of a prefix.
+fn ipnet_bits(net: &IpNet) -> (u128, u8, u8) {
+    match net {
+        IpNet::V4(n) => (u32::from(n.network()) as u128, 32, n.prefix_len()),
+        IpNet::V6(n) => (u128::from(n.network()), 128, n.prefix_len()),
+    }
+}
+
+// The inverse of `ipnet_bits`.
+fn bits_to_ipnet(bits: u128, width: u8, len: u8) -> IpNet {
+    if width == 32 {
+        IpNet::V4(Ipv4Net::new(Ipv4Addr::from(bits as u32), len).expect("valid IPv4 prefix length"))
+    } else {
+        IpNet::V6(Ipv6Net::new(Ipv6Addr::from(bits), len).expect("valid IPv6 prefix length"))
+    }
+}
+
+// Aggregate `nets` into a patricia trie per address family, subtract `excludes` from it, and
+// re-run adjacency merging on the survivors so the output stays maximally aggregated.
+fn trie_aggregate(nets: &[IpNet], excludes: &[IpNet]) -> Vec<IpNet> {
+    let mut v4_root: Option<Box<TrieNode>> = None;
+    let mut v6_root: Option<Box<TrieNode>> = None;
+
+    for net in nets {
+        let (bits, width, len) = ipnet_bits(net);
+        let root = if width == 32 { &mut v4_root } else { &mut v6_root };
+        trie_insert(root, bits, width, 0, len);
+    }
+    for net in excludes {
+        let (bits, width, len) = ipnet_bits(net);
+        let root = if width == 32 { &mut v4_root } else { &mut v6_root };
+        trie_exclude(root, bits, width, 0, len);
+    }
+
+    let mut survivors: Vec<(u128, u8, u8)> = Vec::new();
+    if let Some(root) = &v4_root {
+        trie_collect(root, 0, 32, 0, &mut survivors);
+    }
+    if let Some(root) = &v6_root {
+        trie_collect(root, 0, 128, 0, &mut survivors);
+    }
+
+    let survivor_nets: Vec<IpNet> = survivors
+        .into_iter()
+        .map(|(bits, width, len)| bits_to_ipnet(bits, width, len))
+        .collect();
+    IpNet::aggregate(&survivor_nets)
+}
+
+// The low (inclusive) address bound of a prefix and, when representable, its high (exclusive)
+// bound, as carried by the pair of elements nftables uses to represent a range in an interval
+// set.  A prefix whose block reaches the address family's very last address (most notably
+// `::/0`, since `0.0.0.0/0`'s exclusive bound fits comfortably in a u128) has no representable
+// exclusive bound: `None` signals "omit the end element", which is how nftables itself encodes
+// an interval that runs off the top of the set.
+fn interval_bounds(net: &IpNet) -> (u128, Option<u128>) {
+    let (bits, width, prefix_len) = ipnet_bits(net);
+    let host_bits = width - prefix_len;
+    let span: u128 = if host_bits == 0 {
+        0
+    } else if host_bits >= 128 {
+        u128::MAX
+    } else {
+        (1u128 << host_bits) - 1
+    };
+    let high = bits.checked_add(span).and_then(|last| last.checked_add(1));
+    (bits, high)
+}
+
+// The inverse of `interval_bounds`: recover the CIDR prefix a set's (low, high) element pair
+// represents, if that range happens to fall on a power-of-two-aligned block (which is all we
+// ever write, but a set shared with other tooling might not be).  `high = None` means the pair
+// had no end element, i.e. the range runs to the address family's last address.
+fn interval_to_ipnet(low: u128, high: Option<u128>, width: u8) -> Option<IpNet> {
+    let count = match high {
+        Some(high) => high.checked_sub(low)?,
+        // With no end element the range runs to the top of the u128 space, so its size is
+        // 2^128 - low.  For `low == 0` that is 2^128 itself, which doesn't fit in a u128: it is
+        // the whole address space.
+        None if low == 0 => return Some(bits_to_ipnet(0, width, 0)),
+        None => 0u128.wrapping_sub(low), // 2^128 - low, taken mod 2^128
+    };
+    if count == 0 || !count.is_power_of_two() {
+        return None;
+    }
+    let host_bits = count.trailing_zeros() as u8;
+    if host_bits > width || low & count.wrapping_sub(1) != 0 {
+        return None;
+    }
+    Some(bits_to_ipnet(low, width, width - host_bits))
+}
+
+// Reconcile the named nftables set with the given prefixes: prefixes present in the set but no
+// longer wanted are deleted and missing ones added, in a single netlink transaction so the set is
+// never observably empty.  The set is not flushed: ranges in it that are not CIDR-aligned are
+// left in place.  IPv4 and IPv6 are handled as
+// two distinct sets, since nftnl's `SetKey` is implemented per fixed-width address type rather
+// than the variable-width `IpAddr`.
+fn update_nft_set(table: &str, set: &str, nets: &[IpNet]) -> anyhow::Result<()> {
+    let table_name = CString::new(table).context("table name must not contain a NUL byte")?;
+    let set_name = CString::new(set).context("set name must not contain a NUL byte")?;
+
+    let mut batch = Batch::new();
+
+    let v4_table = Table::new(&table_name, ProtoFamily::Ipv4);
+    let v4_wanted: Vec<IpNet> = nets.iter().copied().filter(|n| matches!(n, IpNet::V4(_))).collect();
+    let v4_current = query_nft_set_elems::<Ipv4Addr>(&v4_table, &set_name, ProtoFamily::Ipv4, 32, |a| {
+        u32::from(a) as u128
+    })?;
+    let (v4_added, v4_removed) = sync_nft_set::<Ipv4Addr>(
+        &mut batch,
+        &v4_table,
+        &set_name,
+        ProtoFamily::Ipv4,
+        &v4_wanted,
+        &v4_current,
+        |bits| Ipv4Addr::from(bits as u32),
+    );
+
+    let v6_table = Table::new(&table_name, ProtoFamily::Ipv6);
+    let v6_wanted: Vec<IpNet> = nets.iter().copied().filter(|n| matches!(n, IpNet::V6(_))).collect();
+    let v6_current = query_nft_set_elems::<Ipv6Addr>(&v6_table, &set_name, ProtoFamily::Ipv6, 128, u128::from)?;
+    let (v6_added, v6_removed) = sync_nft_set::<Ipv6Addr>(
+        &mut batch,
+        &v6_table,
+        &set_name,
+        ProtoFamily::Ipv6,
+        &v6_wanted,
+        &v6_current,
+        Ipv6Addr::from,
+    );
+
+    let added = v4_added + v6_added;
+    let removed = v4_removed + v6_removed;
+    if added == 0 && removed == 0 {
+        debug!("nftables set {}/{} already up to date", table, set);
+        return Ok(());
+    }
+
+    let finalized_batch: FinalizedBatch = batch.finalize();
+    send_and_process(&finalized_batch)?;
+    info!(
+        "Updated nftables set {}/{}: {} added, {} removed",
+        table, set, added, removed
+    );
+    Ok(())
+}
+
+// Diff `wanted` prefixes against `current` ones for a single address family and queue the
+// interval-set element deletes/adds needed to reconcile them onto `batch`.  Returns the
+// (added, removed) prefix counts.
+fn sync_nft_set<A: SetKey + Copy>(
+    batch: &mut Batch,
+    nftnl_table: &Table,
+    set_name: &CString,
+    family: ProtoFamily,
+    wanted: &[IpNet],
+    current: &[IpNet],
+    from_bits: impl Fn(u128) -> A,
+) -> (usize, usize) {
+    let to_remove: Vec<&IpNet> = current.iter().filter(|n| !wanted.contains(n)).collect();
+    let to_add: Vec<&IpNet> = wanted.iter().filter(|n| !current.contains(n)).collect();
+
+    if to_remove.is_empty() && to_add.is_empty() {
+        return (0, 0);
+    }
+
+    batch.add(nftnl_table, MsgType::Add);
+
+    let mut del_set: Set<A> = Set::new(set_name, 0, nftnl_table, family);
+    del_set.set_interval(true);
+    for net in &to_remove {
+        let (low, high) = interval_bounds(net);
+        del_set.add(from_bits(low), false);
+        if let Some(high) = high {
+            del_set.add(from_bits(high), true);
+        }
+    }
+    batch.add(&del_set, MsgType::Del);
+
+    let mut add_set: Set<A> = Set::new(set_name, 0, nftnl_table, family);
+    add_set.set_interval(true);
+    for net in &to_add {
+        let (low, high) = interval_bounds(net);
+        add_set.add(from_bits(low), false);
+        if let Some(high) = high {
+            add_set.add(from_bits(high), true);
+        }
+    }
+    batch.add(&add_set, MsgType::Add);
+
+    (to_add.len(), to_remove.len())
+}
+
+// Fetch the prefixes currently present in an nftables interval set via a netlink GET request,
+// pairing up each (low, high-exclusive) element into an `IpNet`.
+fn query_nft_set_elems<A: SetKey + Copy>(
+    table: &Table,
+    set_name: &CString,
+    family: ProtoFamily,
+    width: u8,
+    to_bits: impl Fn(A) -> u128,
+) -> anyhow::Result<Vec<IpNet>> {
+    let socket = mnl::Socket::new(mnl::Bus::Netfilter).context("failed to open netlink socket")?;
+    let get_set = nftnl::set::get_set_req(table, set_name, family);
+    socket
+        .sendto(&get_set)
+        .context("failed to send netlink GET request")?;
+
+    let mut elems: Vec<(A, bool)> = Vec::new();
+    let portid = socket.portid();
+    let mut buf = vec![0; nftnl::nft_nlmsg_maxsize() as usize];
+    loop {
+        let n = socket.recvfrom(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        match mnl::cb_run(&buf[..n], 0, portid)? {
+            mnl::CbResult::Stop => break,
+            mnl::CbResult::Ok => {
+                elems.extend(nftnl::set::elems_from_msg::<A>(&buf[..n])?);
+            }
+        }
+    }
+
+    let mut nets = Vec::new();
+    let mut pending_low: Option<u128> = None;
+    for (addr, is_interval_end) in elems {
+        let bits = to_bits(addr);
+        if is_interval_end {
+            let Some(low) = pending_low.take() else {
+                continue;
+            };
+            match interval_to_ipnet(low, Some(bits), width) {
+                Some(net) => nets.push(net),
+                None => warn!("nftables set has a range that isn't CIDR-aligned; ignoring it"),
+            }
+        } else if let Some(low) = pending_low.replace(bits) {
+            // The previous element never got a matching end marker: it was a range that runs
+            // to the address family's last address, which we encode without one (see
+            // `interval_bounds`).
+            match interval_to_ipnet(low, None, width) {
+                Some(net) => nets.push(net),
+                None => warn!("nftables set has a range that isn't CIDR-aligned; ignoring it"),
+            }
+        }
+    }
+    if let Some(low) = pending_low {
+        match interval_to_ipnet(low, None, width) {
+            Some(net) => nets.push(net),
+            None => warn!("nftables set has a range that isn't CIDR-aligned; ignoring it"),
+        }
+    }
+    Ok(nets)
+}
+
+// Send a finalized batch of netlink messages and wait for the kernel to ack every one.
+fn send_and_process(batch: &FinalizedBatch) -> anyhow::Result<()> {
+    let socket = mnl::Socket::new(mnl::Bus::Netfilter).context("failed to open netlink socket")?;
+    socket
+        .send_all(batch)
+        .context("failed to send netlink batch")?;
+
+    let portid = socket.portid();
+    let mut buf = vec![0; nftnl::nft_nlmsg_maxsize() as usize];
+    loop {
+        let n = socket.recvfrom(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        match mnl::cb_run(&buf[..n], 0, portid) {
+            Ok(mnl::CbResult::Stop) => break,
+            Ok(mnl::CbResult::Ok) => continue,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => break,
+            Err(e) => bail!(e),
+        }
+    }
+    Ok(())
+}
+
+// Route table IDs above 255 don't fit in RouteHeader::table and must instead be carried in an
+// RTA_TABLE attribute, with the header field set to this compat marker.
+const RT_TABLE_COMPAT: u8 = 252;
+const RTPROT_STATIC: u8 = 4;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_BLACKHOLE: u8 = 6;
+const RTN_UNREACHABLE: u8 = 7;
+const RTN_PROHIBIT: u8 = 8;
+const RTN_THROW: u8 = 9;
+
+// Diff the aggregated prefixes against whatever is currently installed in the given rtnetlink
+// routing table and issue only the RTM_NEWROUTE/RTM_DELROUTE messages needed to reconcile them.
+fn install_routes(table: u32, route_type: RouteType, nets: &[IpNet]) -> anyhow::Result<()> {
+    let socket = Socket::new(NETLINK_ROUTE).context("failed to open rtnetlink socket")?;
+    socket
+        .bind_auto()
+        .context("failed to bind rtnetlink socket")?;
+    socket
+        .connect(&SocketAddr::new(0, 0))
+        .context("failed to connect rtnetlink socket")?;
+
+    let current = query_routes(&socket, table, route_type)?;
+    let to_remove: Vec<&IpNet> = current.iter().filter(|n| !nets.contains(n)).collect();
+    let to_add: Vec<&IpNet> = nets.iter().filter(|n| !current.contains(n)).collect();
+
+    for net in &to_remove {
+        send_route_message(&socket, route_message(table, route_type, net), false)?;
+    }
+    for net in &to_add {
+        send_route_message(&socket, route_message(table, route_type, net), true)?;
+    }
+
+    if to_add.is_empty() && to_remove.is_empty() {
+        debug!("Routing table {} already up to date", table);
+    } else {
+        info!(
+            "Updated routing table {}: {} added, {} removed",
+            table,
+            to_add.len(),
+            to_remove.len()
+        );
+    }
+    Ok(())
+}
+
+// Build the RouteMessage for installing or removing `net` as a null route of the given kind.
+fn route_message(table: u32, route_type: RouteType, net: &IpNet) -> RouteMessage {
+    let (address_family, destination, prefix_len) = match net {
+        IpNet::V4(n) => (libc::AF_INET as u8, n.network().octets().to_vec(), n.prefix_len()),
+        IpNet::V6(n) => (
+            libc::AF_INET6 as u8,
+            n.network().octets().to_vec(),
+            n.prefix_len(),
+        ),
+    };
+
+    let mut message = RouteMessage::default();
+    message.header.address_family = address_family;
+    message.header.destination_prefix_length = prefix_len;
+    message.header.protocol = RTPROT_STATIC;
+    message.header.scope = RT_SCOPE_UNIVERSE;
+    message.header.kind = route_type.rtn_kind();
+    message.header.table = if table <= 255 { table as u8 } else { RT_TABLE_COMPAT };
+    message.nlas.push(RouteNla::Destination(destination));
+    if table > 255 {
+        message.nlas.push(RouteNla::Table(table));
+    }
+    message
+}
+
+// Send a single RTM_NEWROUTE (`is_add`) or RTM_DELROUTE message and wait for its ack.
+fn send_route_message(socket: &Socket, route: RouteMessage, is_add: bool) -> anyhow::Result<()> {
+    let mut nl_msg = NetlinkMessage::from(if is_add {
+        RtnlMessage::NewRoute(route)
+    } else {
+        RtnlMessage::DelRoute(route)
+    });
+    nl_msg.header.flags = NLM_F_REQUEST | NLM_F_ACK | if is_add { NLM_F_CREATE | NLM_F_EXCL } else { 0 };
+    nl_msg.finalize();
+    let mut buf = vec![0; nl_msg.buffer_len()];
+    nl_msg.serialize(&mut buf);
+    socket.send(&buf, 0).context("failed to send rtnetlink message")?;
+
+    let mut recv_buf = vec![0; 4096];
+    let n = socket.recv(&mut recv_buf, 0)?;
+    let reply = NetlinkMessage::<RtnlMessage>::deserialize(&recv_buf[..n])?;
+    if let NetlinkPayload::Error(e) = reply.payload {
+        if e.code != 0 {
+            bail!("rtnetlink request failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// The effective routing table id of a route: the RTA_TABLE attribute if present (carrying table
+// ids too large for the header's 8-bit field, per `route_message`), otherwise the header's
+// `table` byte.
+fn route_table(route: &RouteMessage) -> u32 {
+    route
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            RouteNla::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(route.header.table as u32)
+}
+
+// Dump the routes currently installed in `table` that match `route_type` and were written by
+// this tool (protocol RTPROT_STATIC), across both address families, and return their destination
+// prefixes.  Restricting by protocol keeps us from touching routes some other process installed
+// in the same table for an unrelated reason.
+fn query_routes(socket: &Socket, table: u32, route_type: RouteType) -> anyhow::Result<Vec<IpNet>> {
+    let mut results = Vec::new();
+    for address_family in [libc::AF_INET as u8, libc::AF_INET6 as u8] {
+        let mut get = RouteMessage::default();
+        get.header.address_family = address_family;
+        let mut nl_msg = NetlinkMessage::from(RtnlMessage::GetRoute(get));
+        nl_msg.header.flags = NLM_F_REQUEST | NLM_F_DUMP;
+        nl_msg.finalize();
+        let mut buf = vec![0; nl_msg.buffer_len()];
+        nl_msg.serialize(&mut buf);
+        socket.send(&buf, 0).context("failed to send rtnetlink dump request")?;
+
+        'dump: loop {
+            let mut recv_buf = vec![0; 8192];
+            let n = socket.recv(&mut recv_buf, 0)?;
+            let mut offset = 0;
+            while offset < n {
+                let reply = NetlinkMessage::<RtnlMessage>::deserialize(&recv_buf[offset..n])?;
+                offset += reply.header.length as usize;
+                match reply.payload {
+                    NetlinkPayload::Done(_) => break 'dump,
+                    NetlinkPayload::InnerMessage(RtnlMessage::NewRoute(route))
+                        if route.header.protocol == RTPROT_STATIC
+                            && route.header.kind == route_type.rtn_kind()
+                            && route_table(&route) == table =>
+                    {
+                        if let Some(net) = route_to_ipnet(&route) {
+                            results.push(net);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(results)
+}
+
+// Reconstruct the destination prefix of a route from its RTA_DST attribute and prefix length.
+fn route_to_ipnet(route: &RouteMessage) -> Option<IpNet> {
+    let destination = route.nlas.iter().find_map(|nla| match nla {
+        RouteNla::Destination(bytes) => Some(bytes.clone()),
+        _ => None,
+    })?;
+    match route.header.address_family {
+        f if f == libc::AF_INET as u8 => {
+            let bytes: [u8; 4] = destination.try_into().ok()?;
+            IpNet::new(
+                IpAddr::V4(Ipv4Addr::from(bytes)),
+                route.header.destination_prefix_length,
+            )
+            .ok()
+        }
+        f if f == libc::AF_INET6 as u8 => {
+            let bytes: [u8; 16] = destination.try_into().ok()?;
+            IpNet::new(
+                IpAddr::V6(Ipv6Addr::from(bytes)),
+                route.header.destination_prefix_length,
+            )
+            .ok()
+        }
+        _ => None,
+    }
 }
 
 // True iff a character would be expected in an IPv4 or IPv6 network address.
@@ -192,6 +1024,22 @@ fn extract_nets(s: &str) -> Vec<IpNet> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_route_message_table_over_255_round_trips_via_rta_table() {
+        let net = "192.0.2.0/24".parse::<IpNet>().unwrap();
+        let route = route_message(300, RouteType::Blackhole, &net);
+        assert_eq!(route.header.table, RT_TABLE_COMPAT);
+        assert_eq!(route_table(&route), 300);
+    }
+
+    #[test]
+    fn test_route_message_table_under_256_fits_in_header() {
+        let net = "192.0.2.0/24".parse::<IpNet>().unwrap();
+        let route = route_message(200, RouteType::Blackhole, &net);
+        assert_eq!(route.header.table, 200);
+        assert_eq!(route_table(&route), 200);
+    }
+
     #[test]
     fn test_just_the_net() {
         // Use prefixes from RFCs 3849 and 5737.
@@ -235,6 +1083,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_trie_aggregate_no_excludes() {
+        let nets = vec![
+            "192.0.2.0/25".parse::<IpNet>().unwrap(),
+            "192.0.2.128/25".parse::<IpNet>().unwrap(),
+        ];
+        assert_eq!(
+            trie_aggregate(&nets, &[]),
+            vec!["192.0.2.0/24".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_trie_aggregate_exclude_splits_covered_prefix() {
+        let nets = vec!["192.0.2.0/24".parse::<IpNet>().unwrap()];
+        let excludes = vec!["192.0.2.128/25".parse::<IpNet>().unwrap()];
+        let mut got = trie_aggregate(&nets, &excludes);
+        got.sort();
+        assert_eq!(got, vec!["192.0.2.0/25".parse::<IpNet>().unwrap()]);
+    }
+
+    #[test]
+    fn test_trie_aggregate_exclude_broader_drops_whole_prefix() {
+        let nets = vec!["192.0.2.0/25".parse::<IpNet>().unwrap()];
+        let excludes = vec!["192.0.2.0/24".parse::<IpNet>().unwrap()];
+        assert_eq!(trie_aggregate(&nets, &excludes), Vec::<IpNet>::new());
+    }
+
     #[test]
     fn test_write_nets() {
         let nets = vec![
@@ -250,4 +1126,64 @@ mod tests {
             "192.0.2.0/24\n2001:db8:1234:5678:90ab:cdef::/96\n"
         );
     }
+
+    #[test]
+    fn test_interval_bounds_ipv4_default_route_round_trips() {
+        let net: IpNet = "0.0.0.0/0".parse().unwrap();
+        let (low, high) = interval_bounds(&net);
+        assert_eq!(low, 0);
+        assert_eq!(high, Some(1u128 << 32));
+        assert_eq!(interval_to_ipnet(low, high, 32), Some(net));
+    }
+
+    #[test]
+    fn test_interval_bounds_ipv6_default_route_round_trips() {
+        let net: IpNet = "::/0".parse().unwrap();
+        let (low, high) = interval_bounds(&net);
+        assert_eq!(low, 0);
+        // The exclusive upper bound would be 2^128, which doesn't fit in a u128: the pair has no
+        // end element at all, matching nftables' own encoding for a range reaching the top of
+        // the address space.
+        assert_eq!(high, None);
+        assert_eq!(interval_to_ipnet(low, high, 128), Some(net));
+    }
+
+    #[test]
+    fn test_interval_bounds_ipv6_top_half_has_no_end_element() {
+        let net: IpNet = "8000::/1".parse().unwrap();
+        let (low, high) = interval_bounds(&net);
+        assert_eq!(high, None);
+        assert_eq!(interval_to_ipnet(low, high, 128), Some(net));
+    }
+
+    #[test]
+    fn test_interval_to_ipnet_rejects_non_cidr_aligned_range() {
+        assert_eq!(interval_to_ipnet(10, Some(19), 32), None);
+    }
+
+    #[test]
+    fn test_cache_sidecar_path() {
+        assert_eq!(
+            cache_sidecar_path(Path::new("/etc/fetch_iplist/out.txt")),
+            Path::new("/etc/fetch_iplist/out.txt.cache.json")
+        );
+    }
+
+    #[test]
+    fn test_files_identical_same_contents() {
+        let mut a = NamedTempFile::new().unwrap();
+        let mut b = NamedTempFile::new().unwrap();
+        a.write_all(b"same\n").unwrap();
+        b.write_all(b"same\n").unwrap();
+        assert!(files_identical(a.path(), b.path()).unwrap());
+    }
+
+    #[test]
+    fn test_files_identical_different_contents() {
+        let mut a = NamedTempFile::new().unwrap();
+        let mut b = NamedTempFile::new().unwrap();
+        a.write_all(b"one\n").unwrap();
+        b.write_all(b"two\n").unwrap();
+        assert!(!files_identical(a.path(), b.path()).unwrap());
+    }
 }